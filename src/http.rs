@@ -1,16 +1,36 @@
 extern crate hyper;
 extern crate rand;
 
-use hyper::client::{Client};
+use hyper::client::Client;
+use hyper::client::pool::Pool;
 use hyper::header::ContentLength;
 use hyper::server::{Server, Handler, Request, Response};
 use hyper::uri::RequestUri;
-use hyper::net::Openssl;
+use hyper::net::{HttpsConnector, NetworkConnector, Openssl};
 use std::io::Read;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Sender};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::thread;
 
+/// A `NetworkConnector` wrapper that counts how many times `connect` is actually asked to open a
+/// new underlying socket. Used to show the difference between connection-per-request and
+/// keep-alive reuse: with keep-alive working, several requests to the same host should only
+/// bump this once.
+struct CountingConnector<C> {
+    inner: C,
+    connections_opened: Arc<AtomicUsize>,
+}
+
+impl<C: NetworkConnector> NetworkConnector for CountingConnector<C> {
+    type Stream = C::Stream;
+
+    fn connect(&self, host: &str, port: u16, scheme: &str) -> hyper::Result<Self::Stream> {
+        self.connections_opened.fetch_add(1, Ordering::SeqCst);
+        self.inner.connect(host, port, scheme)
+    }
+}
+
 /// This handler has a channel to communicate a request to close the listening server.
 struct CloseServerHandler {
     close_chan: Mutex<Sender<()>>,
@@ -90,7 +110,20 @@ fn main() {
             format!("https://localhost:{}{}", port, path)
         };
 
-        let client = Client::new();
+        // A client built on a counting connector wrapped in hyper's connection `Pool`: it's the
+        // pool, not the `Client` itself, that actually caches and reuses a connection per host,
+        // so with `keep_alive` working all the requests below should share just one TLS
+        // connection instead of each opening a fresh one.
+        let connections_opened = Arc::new(AtomicUsize::new(0));
+        let connector = CountingConnector {
+            inner: HttpsConnector::new(Openssl::with_cert_and_key(
+                "assets/server.crt",
+                "assets/server.key"
+            ).unwrap()),
+            connections_opened: connections_opened.clone(),
+        };
+        let pool = Pool::with_connector(Default::default(), connector);
+        let client = Client::with_connector(pool);
 
         // Normal request
         {
@@ -120,9 +153,18 @@ fn main() {
             let res = client.post(&url("/close")).send().unwrap();
             assert_eq!(res.status, hyper::Ok);
         }
+
+        // All four requests above should have been served over the single connection the
+        // server's `keep_alive(30s)` lets us reuse.
+        let opened = connections_opened.load(Ordering::SeqCst);
+        println!("Underlying TLS connections opened for 4 requests: {}", opened);
+        assert_eq!(opened, 1);
     });
 
-    // And now we wait!
+    // And now we wait! Unwrap the client's result so a failed assertion made after the server
+    // has closed (e.g. the keep-alive connection count being off) actually fails the process
+    // instead of being swallowed. An assertion failing before `POST /close` leaves the server
+    // running, so the join on it below does not return.
     let _srv_wait = srv.join();
-    let _cli_wait = cli.join();
+    cli.join().unwrap();
 }
@@ -1,29 +1,132 @@
 extern crate bytes;
 extern crate mio;
 
-use bytes::{Buf, Take};
+use bytes::{Bytes, BytesMut};
 use mio::{TryRead, TryWrite};
 use mio::tcp::{TcpListener, TcpStream};
+use mio::unix::{UnixListener, UnixStream};
 use mio::util::Slab;
-use std::io::Cursor;
+use std::env;
+use std::io;
 use std::mem;
 
 /// Reserved token for the listener used by the server.
 const SERVER: mio::Token = mio::Token(0);
 
+/// A listening socket, over either TCP or a Unix domain socket. The rest of the server only
+/// ever deals with `Listener`/`Stream`, so the echo logic doesn't care which transport it's
+/// running over.
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Accepts a pending connection, if any, as a `Stream` of the same transport.
+    fn accept(&self) -> io::Result<Option<Stream>> {
+        match *self {
+            Listener::Tcp(ref listener) => {
+                match listener.accept() {
+                    Ok(accepted) => Ok(accepted.map(|(socket, _)| Stream::Tcp(socket))),
+                    Err(e) => Err(e),
+                }
+            },
+            Listener::Unix(ref listener) => {
+                match listener.accept() {
+                    Ok(accepted) => Ok(accepted.map(Stream::Unix)),
+                    Err(e) => Err(e),
+                }
+            },
+        }
+    }
+
+    /// Registers this listener with the event loop under the `SERVER` token.
+    fn register(&self, event_loop: &mut mio::EventLoop<Pong>) {
+        let interest = mio::EventSet::readable();
+        let poll_opt = mio::PollOpt::edge();
+        match *self {
+            Listener::Tcp(ref listener) => {
+                event_loop.register(listener, SERVER, interest, poll_opt).unwrap();
+            },
+            Listener::Unix(ref listener) => {
+                event_loop.register(listener, SERVER, interest, poll_opt).unwrap();
+            },
+        }
+    }
+}
+
+/// A connected socket, over either TCP or a Unix domain socket.
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Registers this connection's socket with the event loop.
+    fn register(&self, event_loop: &mut mio::EventLoop<Pong>, token: mio::Token, interest: mio::EventSet, poll_opt: mio::PollOpt) {
+        match *self {
+            Stream::Tcp(ref socket) => {
+                event_loop.register(socket, token, interest, poll_opt).unwrap();
+            },
+            Stream::Unix(ref socket) => {
+                event_loop.register(socket, token, interest, poll_opt).unwrap();
+            },
+        }
+    }
+
+    /// Re-registers this connection's socket with the event loop.
+    fn reregister(&self, event_loop: &mut mio::EventLoop<Pong>, token: mio::Token, interest: mio::EventSet, poll_opt: mio::PollOpt) {
+        match *self {
+            Stream::Tcp(ref socket) => {
+                event_loop.reregister(socket, token, interest, poll_opt).unwrap();
+            },
+            Stream::Unix(ref socket) => {
+                event_loop.reregister(socket, token, interest, poll_opt).unwrap();
+            },
+        }
+    }
+}
+
+impl TryRead for Stream {
+    fn try_read(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        match *self {
+            Stream::Tcp(ref mut socket) => socket.try_read(buf),
+            Stream::Unix(ref mut socket) => socket.try_read(buf),
+        }
+    }
+}
+
+impl TryWrite for Stream {
+    fn try_write(&mut self, buf: &[u8]) -> io::Result<Option<usize>> {
+        match *self {
+            Stream::Tcp(ref mut socket) => socket.try_write(buf),
+            Stream::Unix(ref mut socket) => socket.try_write(buf),
+        }
+    }
+}
+
 /// Each connection can be in one of three states:
 ///  - Reading stuff that the client is sending,
 ///  - Writing back to the client,
 ///  - Closed, when the client is gone.
+///
+/// The reading buffer is a `BytesMut` we append freshly-read bytes into. Once it holds a full
+/// line, the Writing state splits it into the line being written out and whatever's left over
+/// (a second line the client pipelined in the same read, say); both halves share the same
+/// underlying allocation, so the split is just a pointer/length adjustment, not a copy. Note
+/// that mio 0.5's `try_read_buf`/`try_write_buf` are generic over its own `MutBuf`/`Buf` traits
+/// (pulled in from `bytes` 0.3, which predates `BytesMut`), so they can't be used here:
+/// `Connection::read`/`write` instead go through the plain, non-generic
+/// `TryRead::try_read`/`TryWrite::try_write` and manage these buffers by hand.
 enum State {
-    Reading(Vec<u8>),
-    Writing(Take<Cursor<Vec<u8>>>),
+    Reading(BytesMut),
+    Writing(Bytes, BytesMut),
     Closed,
 }
 
 impl State {
     /// Returns the mutable reading buffer if in the Reading state, panic otherwise.
-    fn mut_read_buf(&mut self) -> &mut Vec<u8> {
+    fn mut_read_buf(&mut self) -> &mut BytesMut {
         match *self {
             State::Reading(ref mut buf) => buf,
             _ => panic!("Connection not readable"),
@@ -31,7 +134,7 @@ impl State {
     }
 
     /// Returns the reading buffer if in the Reading state, panic otherwise.
-    fn read_buf(&self) -> &Vec<u8> {
+    fn read_buf(&self) -> &BytesMut {
         match *self {
             State::Reading(ref buf) => buf,
             _ => panic!("Connection not readable"),
@@ -39,7 +142,7 @@ impl State {
     }
 
     /// Consumes the reading buffer if in the Reading state, panic otherwise.
-    fn unwrap_read_buf(self) -> Vec<u8> {
+    fn unwrap_read_buf(self) -> BytesMut {
         match self {
             State::Reading(buf) => buf,
             _ => panic!("Connection not readable"),
@@ -53,48 +156,48 @@ impl State {
         }
     }
 
-    /// Moves from Reading to Writing.
+    /// Moves from Reading to Writing: splits the line (the first `pos` bytes) off to be written
+    /// back, keeping whatever's left in the reading buffer around so it isn't lost if the client
+    /// already sent a second line.
     fn transition_to_writing(&mut self, pos: usize) {
-        let buf = mem::replace(self, State::Closed).unwrap_read_buf();
-        let buf = Cursor::new(buf);
-        *self = State::Writing(Take::new(buf, pos));
+        let mut buf = mem::replace(self, State::Closed).unwrap_read_buf();
+        let rest = buf.split_off(pos);
+        *self = State::Writing(buf.freeze(), rest);
     }
 
-    /// Returns the mutable writing buffer if in the Writing state, panic otherwise.
-    fn mut_write_buf(&mut self) -> &mut Take<Cursor<Vec<u8>>> {
+    /// Returns the writing buffer if in the Writing state, panic otherwise.
+    fn write_buf(&self) -> &Bytes {
         match *self {
-            State::Writing(ref mut buf) => buf,
+            State::Writing(ref buf, _) => buf,
             _ => panic!("Connection not writeable"),
         }
     }
 
-    /// Returns the writing buffer if in the Writing state, panic otherwise.
-    fn write_buf(&self) -> &Take<Cursor<Vec<u8>>> {
-        match *self {
-            State::Writing(ref buf) => buf,
+    /// Consumes the state if in the Writing state, panic otherwise.
+    fn unwrap_write_buf(self) -> (Bytes, BytesMut) {
+        match self {
+            State::Writing(buf, rest) => (buf, rest),
             _ => panic!("Connection not writeable"),
         }
     }
 
-    /// Consumes the writing buffer if in the Writing state, panic otherwise.
-    fn unwrap_write_buf(self) -> Take<Cursor<Vec<u8>>> {
-        match self {
-            State::Writing(buf) => buf,
+    /// Drops the first `n` bytes of the writing buffer, now that they've been written to the
+    /// socket. `Bytes::split_off` just bumps the start of the shared buffer, so this doesn't
+    /// copy anything.
+    fn advance_write_buf(&mut self, n: usize) {
+        match *self {
+            State::Writing(ref mut buf, _) => { *buf = buf.split_off(n); },
             _ => panic!("Connection not writeable"),
         }
     }
 
-    /// If there's nothing left in the write buffer, transition to the Reading state. Might
-    /// directly transitions back to Writing if something was already in the reading buffer.
+    /// If there's nothing left in the write buffer, transition back to the Reading state,
+    /// carrying over whatever was left over from the line we just wrote. Might immediately
+    /// transition back to Writing if a full second line was already sitting in there.
     fn try_transition_to_reading(&mut self) {
-        if !self.write_buf().has_remaining() {
-            let cursor = mem::replace(self, State::Closed).unwrap_write_buf().into_inner();
-            let pos = cursor.position();
-            let mut buf = cursor.into_inner();
-
-            for _ in 0..pos { buf.remove(0); }
-            *self = State::Reading(buf);
-
+        if self.write_buf().is_empty() {
+            let (_, rest) = mem::replace(self, State::Closed).unwrap_write_buf();
+            *self = State::Reading(rest);
             self.try_transition_to_writing();
         }
     }
@@ -110,96 +213,152 @@ impl State {
 
 /// Represents a client connection on this server.
 struct Connection {
-    socket: TcpStream,
+    socket: Stream,
     token: mio::Token,
     state: State,
+    /// Handle to the idle timeout currently scheduled for this connection, if any.
+    timeout: Option<mio::Timeout>,
 }
 
 impl Connection {
     /// Builds a new connection from a stream and a token, in the Reading state.
-    fn new(socket: TcpStream, token: mio::Token) -> Connection {
+    fn new(socket: Stream, token: mio::Token) -> Connection {
         Connection {
             socket: socket,
             token: token,
-            state: State::Reading(vec![]),
+            state: State::Reading(BytesMut::new()),
+            timeout: None,
         }
     }
 
     /// Called by the server whenever events are ready for this connection.
-    fn ready(&mut self, event_loop: &mut mio::EventLoop<Pong>, events: mio::EventSet) {
+    fn ready(&mut self, event_loop: &mut mio::EventLoop<Pong>, idle_ms: u64, events: mio::EventSet) {
+        // A hangup or socket error means the peer is gone (or worse); either way there's
+        // nothing left to read or write, so just close the connection down.
+        if events.is_hup() || events.is_error() {
+            self.close(event_loop);
+            return;
+        }
+
         match self.state {
             State::Reading(_) => {
                 assert!(events.is_readable());
-                self.read(event_loop);
+                self.read(event_loop, idle_ms);
             },
-            State::Writing(_) => {
+            State::Writing(_, _) => {
                 assert!(events.is_writable());
-                self.write(event_loop);
+                self.write(event_loop, idle_ms);
             }
             _ => panic!("Unexpected state."),
         }
     }
 
-    /// Try to read the data on the socket in the Reading state buffer, and transition to writing
-    /// as needed.
-    fn read(&mut self, event_loop: &mut mio::EventLoop<Pong>) {
-        match self.socket.try_read_buf(self.state.mut_read_buf()) {
+    /// Try to read the data on the socket into the Reading state buffer, and transition to
+    /// writing as needed. Reads land in a fixed-size scratch array first, since `Stream::
+    /// try_read` (unlike mio's generic `try_read_buf`) takes a plain `&mut [u8]`; from there the
+    /// bytes actually read get appended onto the `BytesMut`.
+    fn read(&mut self, event_loop: &mut mio::EventLoop<Pong>, idle_ms: u64) {
+        let mut scratch = [0u8; 4096];
+        match self.socket.try_read(&mut scratch) {
             Ok(Some(0)) => {
                 // If we've read anything yet, let's write it back before closing down.
                 match self.state.read_buf().len() {
                     n if n > 0 => {
                         self.state.transition_to_writing(n);
+                        self.reset_timeout(event_loop, idle_ms);
                         self.reregister(event_loop);
                     }
-                    _ => self.state = State::Closed,
+                    _ => self.close(event_loop),
                 }
             },
-            Ok(Some(_)) => {
+            Ok(Some(n)) => {
+                self.state.mut_read_buf().extend_from_slice(&scratch[..n]);
                 self.state.try_transition_to_writing();
+                self.reset_timeout(event_loop, idle_ms);
                 self.reregister(event_loop);
             },
             Ok(None) => {
                 self.reregister(event_loop);
             },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.reregister(event_loop);
+            },
             Err(e) => {
-                panic!("Connection reading error: {:?}", e);
+                println!("Connection reading error, closing: {:?}", e);
+                self.close(event_loop);
             }
         }
     }
 
-    /// Try to write the data in our Writing state buffer to the socket, and transition as needed.
-    fn write(&mut self, event_loop: &mut mio::EventLoop<Pong>) {
-        match self.socket.try_write_buf(self.state.mut_write_buf()) {
-            Ok(Some(_)) => {
+    /// Try to write the data in our Writing state buffer to the socket, and transition as
+    /// needed. `Bytes` derefs to `&[u8]`, so it can be handed straight to `Stream::try_write`
+    /// (the plain, non-generic write) without going through mio's incompatible `try_write_buf`.
+    fn write(&mut self, event_loop: &mut mio::EventLoop<Pong>, idle_ms: u64) {
+        let result = {
+            let buf = self.state.write_buf();
+            self.socket.try_write(buf)
+        };
+
+        match result {
+            Ok(Some(n)) => {
+                self.state.advance_write_buf(n);
                 self.state.try_transition_to_reading();
+                self.reset_timeout(event_loop, idle_ms);
                 self.reregister(event_loop);
             },
             Ok(None) => {
                 self.reregister(event_loop);
             },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.reregister(event_loop);
+            },
             Err(e) => {
-                panic!("Connection writing error: {:?}", e);
+                println!("Connection writing error, closing: {:?}", e);
+                self.close(event_loop);
             }
         }
     }
 
-    /// Initially register that connection with the event loop.
-    fn register(&self, event_loop: &mut mio::EventLoop<Pong>) {
+    /// Closes the connection down: cancels whatever idle timeout is still scheduled before
+    /// dropping it, so a stale timer can't fire later and hit whatever connection ends up
+    /// reusing this slab slot's token.
+    fn close(&mut self, event_loop: &mut mio::EventLoop<Pong>) {
+        if let Some(timeout) = self.timeout.take() {
+            event_loop.clear_timeout(timeout);
+        }
+        self.state = State::Closed;
+    }
+
+    /// Initially register that connection with the event loop, and arm its idle timeout.
+    fn register(&mut self, event_loop: &mut mio::EventLoop<Pong>, idle_ms: u64) {
         let poll_opt = mio::PollOpt::edge() | mio::PollOpt::oneshot();
-        event_loop.register(&self.socket, self.token, self.get_event_set(), poll_opt).unwrap();
+        self.socket.register(event_loop, self.token, self.get_event_set(), poll_opt);
+        self.reset_timeout(event_loop, idle_ms);
     }
 
     /// Re-register the event we care about depending on the current state of the connection.
     fn reregister(&self, event_loop: &mut mio::EventLoop<Pong>) {
         let poll_opt = mio::PollOpt::edge() | mio::PollOpt::oneshot();
-        event_loop.reregister(&self.socket, self.token, self.get_event_set(), poll_opt).unwrap();
+        self.socket.reregister(event_loop, self.token, self.get_event_set(), poll_opt);
+    }
+
+    /// Cancels whatever idle timeout is currently scheduled and schedules a fresh one, since the
+    /// connection just made progress (or just connected).
+    fn reset_timeout(&mut self, event_loop: &mut mio::EventLoop<Pong>, idle_ms: u64) {
+        if let Some(timeout) = self.timeout.take() {
+            event_loop.clear_timeout(timeout);
+        }
+        self.timeout = event_loop.timeout_ms(self.token, idle_ms).ok();
     }
 
     /// Get the type of events this connection should care about, depending on its current state.
+    /// Hangup and error readiness are always requested too, so a dropped peer or broken socket
+    /// closes just this connection instead of taking down the whole event loop.
     fn get_event_set(&self) -> mio::EventSet {
+        let hup_and_error = mio::EventSet::hup() | mio::EventSet::error();
         match self.state {
-            State::Reading(_) => mio::EventSet::readable(),
-            State::Writing(_) => mio::EventSet::writable(),
+            State::Reading(_) => mio::EventSet::readable() | hup_and_error,
+            State::Writing(_, _) => mio::EventSet::writable() | hup_and_error,
             _ => mio::EventSet::none(),
         }
     }
@@ -210,27 +369,69 @@ impl Connection {
     }
 }
 
+/// Messages that can be sent to a running `Pong` through its event loop channel.
+enum PongMessage {
+    /// Requests a graceful shutdown: stop accepting new connections, then stop the event loop
+    /// once every open connection is gone (closed by its peer, by an error, or by its idle
+    /// timeout). Open connections are not closed just because their writes have drained.
+    Shutdown,
+}
+
+/// A handle that lets embedding code request a graceful stop of a running `Pong` server.
+/// Mirrors `CloseServerHandler::close` from the HTTPS example, but over a mio channel instead
+/// of an `mpsc` one.
+pub struct PongController {
+    sender: mio::Sender<PongMessage>,
+}
+
+impl PongController {
+    /// Requests that the server stop accepting connections, and shut the event loop down once
+    /// none of its connections is left open.
+    pub fn close(&self) {
+        self.sender.send(PongMessage::Shutdown).unwrap();
+    }
+}
+
 /// This is our ping-pong server. A listener, and a bunch of connections.
 struct Pong {
-    listener: TcpListener,
+    listener: Listener,
     connections: Slab<Connection>,
+    /// How long a connection can stay without making progress before it's dropped.
+    idle_ms: u64,
+    /// Set once a shutdown has been requested; new connections are refused from then on.
+    shutting_down: bool,
 }
 
 impl Pong {
     /// Builds a new server from a listener. The connections slab is initialized too.
-    fn new(listener: TcpListener) -> Pong {
+    fn new(listener: Listener, idle_ms: u64) -> Pong {
         let slab = Slab::new_starting_at(mio::Token(1), 1024);
 
         Pong {
             listener: listener,
             connections: slab,
+            idle_ms: idle_ms,
+            shutting_down: false,
+        }
+    }
+
+    /// Builds a `PongController` for this server's event loop. Call before `event_loop.run`.
+    pub fn controller(event_loop: &mio::EventLoop<Pong>) -> PongController {
+        PongController { sender: event_loop.channel() }
+    }
+
+    /// Once a shutdown has been requested, stop the event loop as soon as no connection is left
+    /// in the slab.
+    fn maybe_finish_shutdown(&self, event_loop: &mut mio::EventLoop<Self>) {
+        if self.shutting_down && self.connections.count() == 0 {
+            event_loop.shutdown();
         }
     }
 }
 
 impl mio::Handler for Pong {
-    type Timeout = ();
-    type Message = ();
+    type Timeout = mio::Token;
+    type Message = PongMessage;
 
     /// Called by the event loop whenever an event we care about is happening.
     fn ready(
@@ -241,10 +442,12 @@ impl mio::Handler for Pong {
     ) {
         match token {
             SERVER => {
-                match self.listener.accept() {
-                    Ok(Some(socket_addr)) => {
-                        let (socket, _) = socket_addr;
+                if self.shutting_down {
+                    return;
+                }
 
+                match self.listener.accept() {
+                    Ok(Some(socket)) => {
                         // Make a new connection object and put it in our connections slab.
                         let token = self.connections
                                         .insert_with(|token| Connection::new(socket, token))
@@ -253,7 +456,7 @@ impl mio::Handler for Pong {
 
                         // Make sure the event loop now cares about the events happening to this
                         // connection.
-                        self.connections[token].register(event_loop);
+                        self.connections[token].register(event_loop, self.idle_ms);
                     },
                     Ok(None) => {
                         println!("Socket wasn't ready yet");
@@ -266,7 +469,7 @@ impl mio::Handler for Pong {
             },
             _ => {
                 // Forward what happened to the connection object.
-                self.connections[token].ready(event_loop, events);
+                self.connections[token].ready(event_loop, self.idle_ms, events);
 
                 // Check if the connection is now closed, in which case we can forget about it.
                 if self.connections[token].is_closed() {
@@ -276,27 +479,62 @@ impl mio::Handler for Pong {
                         token.as_usize(),
                         self.connections.count()
                     );
+                    self.maybe_finish_shutdown(event_loop);
                 }
             },
         }
     }
+
+    /// Called by the event loop when a connection's idle timeout fires. The connection hasn't
+    /// made any progress in `idle_ms`, so close it and free its slab slot.
+    fn timeout(&mut self, event_loop: &mut mio::EventLoop<Self>, token: mio::Token) {
+        if self.connections.contains(token) {
+            self.connections.remove(token);
+            println!("Idle timeout, removing the socket with token {}.", token.as_usize());
+            self.maybe_finish_shutdown(event_loop);
+        }
+    }
+
+    /// Called by the event loop when a message arrives on its channel.
+    fn notify(&mut self, event_loop: &mut mio::EventLoop<Self>, message: PongMessage) {
+        match message {
+            PongMessage::Shutdown => {
+                println!("Shutdown requested, refusing new connections and draining.");
+                self.shutting_down = true;
+                self.maybe_finish_shutdown(event_loop);
+            }
+        }
+    }
+}
+
+/// Picks the transport to listen on from the command line: `--unix <path>` binds a Unix domain
+/// socket there, anything else is treated as a TCP address (defaulting to `0.0.0.0:6567`).
+fn bind_listener() -> Listener {
+    let mut args = env::args().skip(1);
+
+    match args.next() {
+        Some(ref flag) if flag == "--unix" => {
+            let path = args.next().expect("--unix requires a socket path");
+            Listener::Unix(UnixListener::bind(&path).unwrap())
+        },
+        Some(address) => {
+            Listener::Tcp(TcpListener::bind(&address.parse().unwrap()).unwrap())
+        },
+        None => {
+            Listener::Tcp(TcpListener::bind(&"0.0.0.0:6567".parse().unwrap()).unwrap())
+        },
+    }
 }
 
 fn main() {
-    // Start listening
-    let address = "0.0.0.0:6567".parse().unwrap();
-    let listener = TcpListener::bind(&address).unwrap();
+    // Start listening, over TCP or a Unix domain socket depending on the command line.
+    let listener = bind_listener();
 
     // Create an event queue, register the listener
     let mut event_loop = mio::EventLoop::new().unwrap();
-    event_loop.register(
-        &listener,
-        SERVER,
-        mio::EventSet::readable(),
-        mio::PollOpt::edge()
-    ).unwrap();
-
-    // Run!
+    listener.register(&mut event_loop);
+
+    // Run! Connections idle for more than 30 seconds get dropped.
     println!("Running pingpong server...");
-    event_loop.run(&mut Pong::new(listener)).unwrap();
+    event_loop.run(&mut Pong::new(listener, 30_000)).unwrap();
 }